@@ -1,31 +1,166 @@
 use std::{
+    collections::HashSet,
     path::{Path, PathBuf},
-    sync::mpsc,
+    sync::{mpsc, Arc, Mutex, Weak},
     thread,
     time::Duration,
 };
 
 use crossbeam_channel::Receiver;
 use drop_bomb::DropBomb;
-use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher};
+use ignore::WalkBuilder;
+use notify::{
+    DebouncedEvent, PollWatcher, RecommendedWatcher, RecursiveMode, Watcher as NotifyWatcher,
+};
+
+/// Directories we have registered a non-recursive watch for.
+///
+/// We descend the roots ourselves instead of handing them to `notify` with
+/// `RecursiveMode::Recursive` so that gitignored trees (`target/`, `.git/`,
+/// ...) never consume an OS watch handle or event bandwidth. The set is shared
+/// with the forwarding thread, which keeps it in sync as directories are
+/// created and removed.
+type WatchedDirs = Arc<Mutex<HashSet<PathBuf>>>;
+
+/// Default debounce window. The forwarding thread waits this long for the
+/// input channel to go quiet before flushing an accumulated batch, and it is
+/// also what `notify` itself is configured with.
+const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// The underlying `notify` watcher. `notify::Watcher` is not dyn-compatible
+/// (it has a `Self: Sized` bound and generic methods), so we dispatch over the
+/// two concrete backends by hand instead of boxing a trait object.
+enum BoxedWatcher {
+    Recommended(RecommendedWatcher),
+    Poll(PollWatcher),
+}
+
+impl BoxedWatcher {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> notify::Result<()> {
+        match self {
+            BoxedWatcher::Recommended(watcher) => watcher.watch(path, mode),
+            BoxedWatcher::Poll(watcher) => watcher.watch(path, mode),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) -> notify::Result<()> {
+        match self {
+            BoxedWatcher::Recommended(watcher) => watcher.unwatch(path),
+            BoxedWatcher::Poll(watcher) => watcher.unwatch(path),
+        }
+    }
+}
+
+/// Which `notify` backend to drive.
+pub enum Backend {
+    /// The platform's native recommended watcher (inotify, FSEvents, ...).
+    Recommended,
+    /// A polling watcher, for network/virtual filesystems where the native
+    /// backend silently delivers nothing.
+    Poll,
+}
+
+/// Whether the output channel applies backpressure.
+pub enum ChannelKind {
+    /// Never blocks the forwarding thread; memory grows if the consumer stalls.
+    Unbounded,
+    /// Blocks the forwarding thread once `capacity` batches are pending.
+    Bounded(usize),
+}
+
+/// Construction-time configuration for a [`Watcher`]. Use the setters and then
+/// [`WatcherConfig::build`]; [`Watcher::new`] is the default-config shortcut.
+pub struct WatcherConfig {
+    debounce: Duration,
+    backend: Backend,
+    channel: ChannelKind,
+    filter: FileFilter,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> WatcherConfig {
+        WatcherConfig {
+            debounce: DEFAULT_DEBOUNCE,
+            backend: Backend::Recommended,
+            channel: ChannelKind::Unbounded,
+            filter: Box::new(|_| true),
+        }
+    }
+}
+
+impl WatcherConfig {
+    pub fn new() -> WatcherConfig {
+        WatcherConfig::default()
+    }
+
+    /// Set how long to wait for the event stream to go quiet before flushing.
+    pub fn debounce(mut self, debounce: Duration) -> WatcherConfig {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Choose the native recommended backend or the polling backend.
+    pub fn backend(mut self, backend: Backend) -> WatcherConfig {
+        self.backend = backend;
+        self
+    }
+
+    /// Choose between an unbounded and a bounded (backpressuring) output channel.
+    pub fn channel(mut self, channel: ChannelKind) -> WatcherConfig {
+        self.channel = channel;
+        self
+    }
+
+    /// Restrict which files get their contents read (see [`FileFilter`]).
+    pub fn filter(mut self, filter: FileFilter) -> WatcherConfig {
+        self.filter = filter;
+        self
+    }
+
+    pub fn build(self) -> Result<Watcher, Box<std::error::Error>> {
+        Watcher::with_config(self)
+    }
+}
 
 pub struct Watcher {
-    receiver: Receiver<WatcherChange>,
-    watcher: RecommendedWatcher,
+    receiver: Receiver<Vec<WatcherChange>>,
+    watcher: Arc<Mutex<BoxedWatcher>>,
+    watched_dirs: WatchedDirs,
+    roots: Arc<Mutex<Vec<PathBuf>>>,
     thread: thread::JoinHandle<()>,
     bomb: DropBomb,
 }
 
-#[derive(Debug)]
+/// Decides which files the watcher reads contents for. Called on the watcher
+/// thread, so consumers can cheaply restrict reads to e.g. `.rs` sources.
+pub type FileFilter = Box<dyn Fn(&Path) -> bool + Send>;
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum WatcherChange {
-    Create(PathBuf),
-    Write(PathBuf),
+    Create { path: PathBuf, text: Option<String> },
+    Write { path: PathBuf, text: Option<String> },
     Remove(PathBuf),
     Rename(PathBuf, PathBuf),
+    /// The backend lost events (buffer overflow, network FS reconnect) for the
+    /// given root. The watcher follows it with synthetic `Create`/`Write`/
+    /// `Remove` changes that reconcile the consumer's view with the real tree.
+    Rescan(PathBuf),
+    /// A watch went dead (e.g. the inotify limit was hit or `path` became
+    /// inaccessible). The forwarding thread attempts to re-register it before
+    /// surfacing this, so the embedding application can react if recovery fails.
+    Error { error: String, path: Option<PathBuf> },
 }
 
 impl WatcherChange {
-    fn from_debounced_event(ev: DebouncedEvent) -> Option<WatcherChange> {
+    /// Decide whether an event is relevant and what change it maps to. File
+    /// contents are *not* read here: this runs under the `watched_dirs` lock,
+    /// and a blocking read would stall `watch`/`sync_watches`/recovery. The
+    /// `text` of a `Create`/`Write` is filled in by [`fill_text`] once the lock
+    /// has been released.
+    fn from_debounced_event(
+        ev: DebouncedEvent,
+        watched_dirs: &HashSet<PathBuf>,
+    ) -> Option<WatcherChange> {
         match ev {
             DebouncedEvent::NoticeWrite(_)
             | DebouncedEvent::NoticeRemove(_)
@@ -34,52 +169,489 @@ impl WatcherChange {
                 // ignore
                 None
             }
-            DebouncedEvent::Create(path) => Some(WatcherChange::Create(path)),
-            DebouncedEvent::Write(path) => Some(WatcherChange::Write(path)),
-            DebouncedEvent::Remove(path) => Some(WatcherChange::Remove(path)),
-            DebouncedEvent::Rename(src, dst) => Some(WatcherChange::Rename(src, dst)),
-            DebouncedEvent::Error(err, path) => {
-                // TODO
-                log::warn!("watch error {}, {:?}", err, path);
+            DebouncedEvent::Create(path) => filter_watched(path, watched_dirs)
+                // a directory create only adjusts watches (see `sync_watches`);
+                // it has no contents to read and nothing to forward
+                .filter(|path| !path.is_dir())
+                .map(|path| WatcherChange::Create { path, text: None }),
+            DebouncedEvent::Write(path) => filter_watched(path, watched_dirs)
+                .filter(|path| !path.is_dir())
+                .map(|path| WatcherChange::Write { path, text: None }),
+            DebouncedEvent::Remove(path) => {
+                filter_watched(path, watched_dirs).map(WatcherChange::Remove)
+            }
+            DebouncedEvent::Rename(src, dst) => {
+                // Unlike a directory Create/Write (pure watch bookkeeping, see
+                // above), a rename carries the src -> dst mapping a consumer
+                // needs to relocate its entries, and `notify` emits no per-child
+                // events for a moved subtree. So we surface renames of both
+                // files and directories, as long as an endpoint is watched.
+                if is_watched(&src, watched_dirs) || is_watched(&dst, watched_dirs) {
+                    Some(WatcherChange::Rename(src, dst))
+                } else {
+                    None
+                }
+            }
+            DebouncedEvent::Error(..) => {
+                // handled (with recovery) on the forwarding thread before we
+                // reach here; see `handle` in `Watcher::with_config`
                 None
             }
         }
     }
 }
 
-impl Watcher {
-    pub fn new() -> Result<Watcher, Box<std::error::Error>> {
-        let (input_sender, input_receiver) = mpsc::channel();
-        let watcher = notify::watcher(input_sender, Duration::from_millis(250))?;
-        let (output_sender, output_receiver) = crossbeam_channel::unbounded();
-        let thread = thread::spawn(move || loop {
-            match input_receiver.recv() {
-                Ok(ev) => {
-                    // forward relevant events only
-                    if let Some(change) = WatcherChange::from_debounced_event(ev) {
-                        output_sender.send(change).unwrap();
+/// An event is relevant only if it concerns a path inside a directory we
+/// actually watch; anything else originates from an ignored tree and must not
+/// be surfaced to consumers.
+fn is_watched(path: &Path, watched_dirs: &HashSet<PathBuf>) -> bool {
+    watched_dirs.contains(path)
+        || path
+            .parent()
+            .map_or(false, |parent| watched_dirs.contains(parent))
+}
+
+fn filter_watched(path: PathBuf, watched_dirs: &HashSet<PathBuf>) -> Option<PathBuf> {
+    if is_watched(&path, watched_dirs) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Read the contents of a file the watcher just observed change, on the same
+/// thread that drains `notify`. Serializing every read here is the point: a
+/// consumer can never see an older version after a newer one, because there is
+/// no second reader racing the notification order. Returns `None` when the
+/// `filter` rejects the path or the read fails (e.g. the path is a directory
+/// or vanished between the event and the read).
+fn read_text(path: &Path, filter: &FileFilter) -> Option<String> {
+    if !filter(path) {
+        return None;
+    }
+    match std::fs::read_to_string(path) {
+        Ok(text) => Some(text),
+        Err(err) => {
+            log::warn!("failed to read {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Fill in the contents of a `Create`/`Write` change after the `watched_dirs`
+/// lock has been released, so the read never blocks watch bookkeeping.
+fn fill_text(change: &mut WatcherChange, filter: &FileFilter) {
+    match change {
+        WatcherChange::Create { path, text } | WatcherChange::Write { path, text } => {
+            *text = read_text(path, filter);
+        }
+        _ => {}
+    }
+}
+
+/// Collapse redundant events within a single debounce window so a consumer can
+/// apply one consistent snapshot of deltas instead of chasing intermediate
+/// states: a `Create` followed by a `Write` on the same path stays a `Create`,
+/// a `Write` superseded by a later `Remove` is dropped, and a
+/// `Create(tmp)` + `Rename(tmp, dst)` pair folds into `Create(dst)`.
+fn coalesce_changes(changes: Vec<WatcherChange>) -> Vec<WatcherChange> {
+    let mut result: Vec<WatcherChange> = Vec::with_capacity(changes.len());
+    for change in changes {
+        match change {
+            WatcherChange::Create { path, text } => {
+                result.push(WatcherChange::Create { path, text })
+            }
+            WatcherChange::Write { path, text } => {
+                // a Write right after a Create is still just a Create, and a
+                // repeated Write on the same path adds nothing but its fresher
+                // contents, which we fold into the existing entry; only the
+                // latest entry on this path counts, so a Remove or Rename in
+                // between keeps this Write separate
+                let prior = result.iter().rposition(|c| match c {
+                    WatcherChange::Create { path: p, .. }
+                    | WatcherChange::Write { path: p, .. }
+                    | WatcherChange::Remove(p) => p == &path,
+                    WatcherChange::Rename(src, dst) => src == &path || dst == &path,
+                    _ => false,
+                });
+                match prior {
+                    Some(idx) => match &mut result[idx] {
+                        WatcherChange::Create { text: t, .. }
+                        | WatcherChange::Write { text: t, .. } => *t = text,
+                        _ => result.push(WatcherChange::Write { path, text }),
+                    },
+                    None => result.push(WatcherChange::Write { path, text }),
+                }
+            }
+            WatcherChange::Remove(path) => {
+                // a Write superseded by a later Remove never needs to surface
+                result.retain(
+                    |c| !matches!(c, WatcherChange::Write { path: p, .. } if p == &path),
+                );
+                result.push(WatcherChange::Remove(path));
+            }
+            WatcherChange::Rename(src, dst) => {
+                let prior = result
+                    .iter()
+                    .position(|c| matches!(c, WatcherChange::Create { path, .. } if path == &src));
+                match prior {
+                    Some(idx) => {
+                        let text = match &result[idx] {
+                            WatcherChange::Create { text, .. } => text.clone(),
+                            _ => None,
+                        };
+                        result[idx] = WatcherChange::Create { path: dst, text };
+                    }
+                    None => result.push(WatcherChange::Rename(src, dst)),
+                }
+            }
+            WatcherChange::Rescan(path) => result.push(WatcherChange::Rescan(path)),
+            WatcherChange::Error { error, path } => {
+                result.push(WatcherChange::Error { error, path })
+            }
+        }
+    }
+    result
+}
+
+/// Walk `root` honoring `.gitignore`/`.ignore` rules and register a
+/// non-recursive watch for every directory we have not seen yet. Returns
+/// whether at least one directory watch was successfully registered, so
+/// callers (e.g. recovery) can tell a real re-registration from a no-op.
+fn watch_recursive(
+    watcher: &Mutex<BoxedWatcher>,
+    watched_dirs: &Mutex<HashSet<PathBuf>>,
+    root: &Path,
+) -> bool {
+    let mut watcher = watcher.lock().unwrap();
+    let mut watched_dirs = watched_dirs.lock().unwrap();
+    let mut watched_any = false;
+    for entry in WalkBuilder::new(root).build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                log::warn!("watch walk error: {}", err);
+                continue;
+            }
+        };
+        if !entry.file_type().map_or(false, |ft| ft.is_dir()) {
+            continue;
+        }
+        let path = entry.into_path();
+        if watched_dirs.insert(path.clone()) {
+            match watcher.watch(&path, RecursiveMode::NonRecursive) {
+                Ok(()) => watched_any = true,
+                Err(err) => {
+                    log::warn!("failed to watch {}: {}", path.display(), err);
+                    watched_dirs.remove(&path);
+                }
+            }
+        }
+    }
+    watched_any
+}
+
+/// Keep the watched-directory set in step with the filesystem: descend into
+/// freshly created directories, forget removed ones (`notify` drops the
+/// underlying watch on removal by itself), and move watches across directory
+/// renames so a renamed subtree keeps being observed.
+fn sync_watches(watcher: &Weak<Mutex<BoxedWatcher>>, watched_dirs: &WatchedDirs, ev: &DebouncedEvent) {
+    let watcher = match watcher.upgrade() {
+        Some(watcher) => watcher,
+        None => return,
+    };
+    match ev {
+        DebouncedEvent::Create(path) if path.is_dir() => {
+            watch_recursive(&watcher, watched_dirs, path);
+        }
+        DebouncedEvent::Remove(path) => {
+            let mut watched_dirs = watched_dirs.lock().unwrap();
+            watched_dirs.retain(|dir| dir != path && !dir.starts_with(path));
+        }
+        DebouncedEvent::Rename(old, new) => {
+            // A rename is not a removal, so `notify` keeps the old descriptors
+            // alive; unwatch every watch under `old` explicitly before dropping
+            // it from the record, otherwise the handles leak and re-walking
+            // `new` can double-watch the same inode.
+            {
+                let mut watcher = watcher.lock().unwrap();
+                let mut watched_dirs = watched_dirs.lock().unwrap();
+                let stale: Vec<PathBuf> = watched_dirs
+                    .iter()
+                    .filter(|dir| *dir == old || dir.starts_with(old))
+                    .cloned()
+                    .collect();
+                for dir in stale {
+                    if let Err(err) = watcher.unwatch(&dir) {
+                        log::warn!("failed to unwatch {}: {}", dir.display(), err);
                     }
+                    watched_dirs.remove(&dir);
                 }
+            }
+            if new.is_dir() {
+                watch_recursive(&watcher, watched_dirs, new);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Files we have already told the consumer about, so a `Rescan` can tell
+/// which files disappeared while events were being dropped.
+type KnownFiles = Arc<Mutex<HashSet<PathBuf>>>;
+
+/// Keep the known-file set in step with a change we are about to forward.
+fn record_known(known_files: &KnownFiles, change: &WatcherChange) {
+    let mut known_files = known_files.lock().unwrap();
+    match change {
+        WatcherChange::Create { path, .. } | WatcherChange::Write { path, .. } => {
+            known_files.insert(path.clone());
+        }
+        WatcherChange::Remove(path) => {
+            known_files.remove(path);
+        }
+        WatcherChange::Rename(src, dst) => {
+            known_files.remove(src);
+            known_files.insert(dst.clone());
+        }
+        WatcherChange::Rescan(_) => {}
+        WatcherChange::Error { .. } => {}
+    }
+}
+
+/// Re-walk every watched root after a `Rescan` and produce the changes that
+/// bring the consumer's view back in line with the filesystem: a `Rescan`
+/// marker per root, a `Create`/`Write` for every file that currently exists,
+/// and a `Remove` for every previously known file that no longer does.
+fn resync(
+    roots: &Mutex<Vec<PathBuf>>,
+    watcher: &Weak<Mutex<BoxedWatcher>>,
+    watched_dirs: &WatchedDirs,
+    known_files: &KnownFiles,
+    filter: &FileFilter,
+) -> Vec<WatcherChange> {
+    let roots = roots.lock().unwrap().clone();
+    let mut changes = Vec::new();
+    let mut current = HashSet::new();
+    for root in &roots {
+        changes.push(WatcherChange::Rescan(root.clone()));
+        // re-register watches, as directories may have appeared while events
+        // were being dropped
+        if let Some(watcher) = watcher.upgrade() {
+            watch_recursive(&watcher, watched_dirs, root);
+        }
+        for entry in WalkBuilder::new(root).build() {
+            let entry = match entry {
+                Ok(entry) => entry,
                 Err(err) => {
-                    log::debug!("Watcher stopped ({})", err);
-                    break;
+                    log::warn!("rescan walk error: {}", err);
+                    continue;
                 }
+            };
+            if entry.file_type().map_or(false, |ft| ft.is_dir()) {
+                continue;
+            }
+            let path = entry.into_path();
+            let text = read_text(&path, filter);
+            let was_known = known_files.lock().unwrap().contains(&path);
+            if was_known {
+                changes.push(WatcherChange::Write { path: path.clone(), text });
+            } else {
+                changes.push(WatcherChange::Create { path: path.clone(), text });
+            }
+            current.insert(path);
+        }
+    }
+    // anything we knew about that the walk no longer sees has been removed
+    let mut known_files = known_files.lock().unwrap();
+    for path in known_files.iter() {
+        if !current.contains(path) {
+            changes.push(WatcherChange::Remove(path.clone()));
+        }
+    }
+    *known_files = current;
+    changes
+}
+
+/// Try to re-register the watch the backend just reported dead. When `path`
+/// names a directory (or a file, whose parent directory we re-walk) we refresh
+/// just that subtree; without a path we fall back to re-registering every root.
+/// Returns whether the affected watch is registered again afterwards.
+fn recover_watch(
+    watcher: &Weak<Mutex<BoxedWatcher>>,
+    watched_dirs: &WatchedDirs,
+    roots: &Mutex<Vec<PathBuf>>,
+    path: &Option<PathBuf>,
+) -> bool {
+    let watcher = match watcher.upgrade() {
+        Some(watcher) => watcher,
+        None => return false,
+    };
+    let target = match path {
+        Some(path) if path.is_dir() => Some(path.clone()),
+        Some(path) => path.parent().map(Path::to_path_buf),
+        None => None,
+    };
+    match target {
+        Some(dir) => {
+            // forget the stale record for this subtree so `watch_recursive`
+            // actually re-registers it rather than skipping it as known
+            watched_dirs
+                .lock()
+                .unwrap()
+                .retain(|d| d != &dir && !d.starts_with(&dir));
+            watch_recursive(&watcher, watched_dirs, &dir)
+        }
+        None => {
+            // no path to target: drop every record so re-walking forces real
+            // watch calls, then report whether any of them actually succeeded
+            watched_dirs.lock().unwrap().clear();
+            let roots = roots.lock().unwrap().clone();
+            let mut recovered = false;
+            for root in &roots {
+                recovered |= watch_recursive(&watcher, watched_dirs, root);
             }
-        });
+            recovered
+        }
+    }
+}
+
+impl Watcher {
+    pub fn new() -> Result<Watcher, Box<std::error::Error>> {
+        WatcherConfig::new().build()
+    }
+
+    /// Like [`Watcher::new`], but only read the contents of files for which
+    /// `filter` returns `true`; other `Create`/`Write` events still surface,
+    /// just with `text: None`.
+    pub fn with_filter(filter: FileFilter) -> Result<Watcher, Box<std::error::Error>> {
+        WatcherConfig::new().filter(filter).build()
+    }
+
+    /// Build a watcher from an explicit [`WatcherConfig`].
+    pub fn with_config(config: WatcherConfig) -> Result<Watcher, Box<std::error::Error>> {
+        let WatcherConfig {
+            debounce,
+            backend,
+            channel,
+            filter,
+        } = config;
+        let (input_sender, input_receiver) = mpsc::channel();
+        let inner = match backend {
+            Backend::Recommended => {
+                BoxedWatcher::Recommended(RecommendedWatcher::new(input_sender, debounce)?)
+            }
+            Backend::Poll => BoxedWatcher::Poll(PollWatcher::new(input_sender, debounce)?),
+        };
+        let watcher = Arc::new(Mutex::new(inner));
+        let watched_dirs: WatchedDirs = Arc::new(Mutex::new(HashSet::new()));
+        let roots: Arc<Mutex<Vec<PathBuf>>> = Arc::new(Mutex::new(Vec::new()));
+        let known_files: KnownFiles = Arc::new(Mutex::new(HashSet::new()));
+        let (output_sender, output_receiver) = match channel {
+            ChannelKind::Unbounded => crossbeam_channel::unbounded(),
+            ChannelKind::Bounded(capacity) => crossbeam_channel::bounded(capacity),
+        };
+        let thread = {
+            let watcher = Arc::downgrade(&watcher);
+            let watched_dirs = Arc::clone(&watched_dirs);
+            let roots = Arc::clone(&roots);
+            let known_files = Arc::clone(&known_files);
+            thread::spawn(move || {
+                // Translate one debounced event into a batch entry: keep our
+                // own watches current before filtering (a directory create
+                // itself only adjusts watches and is not forwarded). All file
+                // reads happen here, on this single thread, to keep the
+                // snapshot monotonic.
+                let mut handle = |ev: DebouncedEvent, batch: &mut Vec<WatcherChange>| {
+                    // a Rescan signals the backend may have dropped events, so
+                    // reconcile the whole tree rather than trust the stream
+                    if let DebouncedEvent::Rescan = ev {
+                        let changes =
+                            resync(&roots, &watcher, &watched_dirs, &known_files, &filter);
+                        batch.extend(changes);
+                        return;
+                    }
+                    // a dead watch is recoverable: try to re-register it, then
+                    // forward the error either way so the consumer can react
+                    if let DebouncedEvent::Error(err, path) = ev {
+                        let recovered =
+                            recover_watch(&watcher, &watched_dirs, &roots, &path);
+                        if recovered {
+                            log::info!("recovered watch after error {}, {:?}", err, path);
+                        } else {
+                            log::error!("failed to recover watch after error {}, {:?}", err, path);
+                        }
+                        batch.push(WatcherChange::Error {
+                            error: err.to_string(),
+                            path,
+                        });
+                        return;
+                    }
+                    sync_watches(&watcher, &watched_dirs, &ev);
+                    let change = {
+                        let watched_dirs = watched_dirs.lock().unwrap();
+                        WatcherChange::from_debounced_event(ev, &watched_dirs)
+                    };
+                    if let Some(mut change) = change {
+                        // read contents only after dropping the lock above
+                        fill_text(&mut change, &filter);
+                        record_known(&known_files, &change);
+                        batch.push(change);
+                    }
+                };
+                loop {
+                    // block for the first event, then accumulate every event
+                    // that arrives before the input channel goes quiet
+                    let first = match input_receiver.recv() {
+                        Ok(ev) => ev,
+                        Err(err) => {
+                            log::debug!("Watcher stopped ({})", err);
+                            break;
+                        }
+                    };
+                    let mut batch = Vec::new();
+                    handle(first, &mut batch);
+                    let mut disconnected = false;
+                    loop {
+                        match input_receiver.recv_timeout(debounce) {
+                            Ok(ev) => handle(ev, &mut batch),
+                            Err(mpsc::RecvTimeoutError::Timeout) => break,
+                            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                                disconnected = true;
+                                break;
+                            }
+                        }
+                    }
+                    let batch = coalesce_changes(batch);
+                    // forward a non-empty, deduplicated batch only
+                    if !batch.is_empty() {
+                        output_sender.send(batch).unwrap();
+                    }
+                    if disconnected {
+                        log::debug!("Watcher stopped (input channel disconnected)");
+                        break;
+                    }
+                }
+            })
+        };
         Ok(Watcher {
             receiver: output_receiver,
             watcher,
+            watched_dirs,
+            roots,
             thread,
             bomb: DropBomb::new(format!("Watcher was not shutdown")),
         })
     }
 
     pub fn watch(&mut self, root: impl AsRef<Path>) -> Result<(), Box<std::error::Error>> {
-        self.watcher.watch(root, RecursiveMode::Recursive)?;
+        let root = root.as_ref();
+        self.roots.lock().unwrap().push(root.to_path_buf());
+        watch_recursive(&self.watcher, &self.watched_dirs, root);
         Ok(())
     }
 
-    pub fn change_receiver(&self) -> &Receiver<WatcherChange> {
+    pub fn change_receiver(&self) -> &Receiver<Vec<WatcherChange>> {
         &self.receiver
     }
 
@@ -93,4 +665,91 @@ impl Watcher {
         }
         res
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create(path: &str) -> WatcherChange {
+        WatcherChange::Create { path: PathBuf::from(path), text: None }
+    }
+
+    fn write(path: &str) -> WatcherChange {
+        WatcherChange::Write { path: PathBuf::from(path), text: None }
+    }
+
+    #[test]
+    fn write_after_create_stays_create() {
+        let out = coalesce_changes(vec![create("a.rs"), write("a.rs")]);
+        assert_eq!(out, vec![create("a.rs")]);
+    }
+
+    #[test]
+    fn repeated_write_is_coalesced() {
+        let out = coalesce_changes(vec![write("a.rs"), write("a.rs")]);
+        assert_eq!(out, vec![write("a.rs")]);
+    }
+
+    #[test]
+    fn coalesced_write_keeps_freshest_text() {
+        let changes = vec![
+            WatcherChange::Create { path: PathBuf::from("a.rs"), text: Some("old".into()) },
+            WatcherChange::Write { path: PathBuf::from("a.rs"), text: Some("new".into()) },
+        ];
+        let out = coalesce_changes(changes);
+        assert_eq!(
+            out,
+            vec![WatcherChange::Create { path: PathBuf::from("a.rs"), text: Some("new".into()) }]
+        );
+    }
+
+    #[test]
+    fn write_superseded_by_remove_is_dropped() {
+        let out = coalesce_changes(vec![write("a.rs"), WatcherChange::Remove(PathBuf::from("a.rs"))]);
+        assert_eq!(out, vec![WatcherChange::Remove(PathBuf::from("a.rs"))]);
+    }
+
+    #[test]
+    fn create_then_rename_folds_into_create_dst() {
+        let changes = vec![
+            create("tmp"),
+            WatcherChange::Rename(PathBuf::from("tmp"), PathBuf::from("a.rs")),
+        ];
+        assert_eq!(coalesce_changes(changes), vec![create("a.rs")]);
+    }
+
+    #[test]
+    fn rename_without_prior_create_is_kept() {
+        let rename = WatcherChange::Rename(PathBuf::from("a.rs"), PathBuf::from("b.rs"));
+        let changes = vec![WatcherChange::Rename(PathBuf::from("a.rs"), PathBuf::from("b.rs"))];
+        assert_eq!(coalesce_changes(changes), vec![rename]);
+    }
+
+    #[test]
+    fn is_watched_matches_dir_and_direct_children() {
+        let mut dirs = HashSet::new();
+        dirs.insert(PathBuf::from("/root/src"));
+
+        // the watched directory itself
+        assert!(is_watched(Path::new("/root/src"), &dirs));
+        // a file directly inside it
+        assert!(is_watched(Path::new("/root/src/lib.rs"), &dirs));
+        // an unwatched sibling tree (e.g. a gitignored `target/`)
+        assert!(!is_watched(Path::new("/root/target/debug.rs"), &dirs));
+        // a grandchild whose parent dir is not itself watched
+        assert!(!is_watched(Path::new("/root/src/nested/deep.rs"), &dirs));
+    }
+
+    #[test]
+    fn filter_watched_passes_through_watched_paths_only() {
+        let mut dirs = HashSet::new();
+        dirs.insert(PathBuf::from("/root/src"));
+
+        assert_eq!(
+            filter_watched(PathBuf::from("/root/src/lib.rs"), &dirs),
+            Some(PathBuf::from("/root/src/lib.rs"))
+        );
+        assert_eq!(filter_watched(PathBuf::from("/root/target/x.rs"), &dirs), None);
+    }
+}